@@ -0,0 +1,250 @@
+// Copyright (C) 2020 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Optional asymmetric token authentication for mutating endpoints
+//! (publish, yank, unyank), based on [PASETO][] v3 public tokens, as
+//! described in [RFC 3231][].
+//!
+//! A token is only accepted if:
+//! - it verifies against one of our [`TrustedKeys`]
+//! - its `sub` claim names a key we actually trust
+//! - its `exp` claim is a valid, non-expired ISO-8601 timestamp
+//! - its `nbf`/`iat` claims are not in the future
+//! - its implicit assertion binds it to this registry's URL and to
+//!   the specific endpoint (and, for publish, crate name/version)
+//!   being invoked, so a token cannot be replayed against a different
+//!   request
+//!
+//! [PASETO]: https://paseto.io
+//! [RFC 3231]: https://www.rfc-editor.org/rfc/rfc3231
+
+use std::collections::HashMap;
+use std::convert::TryFrom as _;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use pasetors::claims::Claims;
+use pasetors::claims::ClaimsValidationRules;
+use pasetors::keys::AsymmetricPublicKey;
+use pasetors::token::UntrustedToken;
+use pasetors::version3::PublicToken;
+use pasetors::version3::V3;
+use pasetors::Public;
+
+
+/// The set of public keys, identified by key id, that we consider
+/// trusted for signing publish/yank/unyank tokens.
+#[derive(Debug)]
+pub struct TrustedKeys {
+  keys: HashMap<String, AsymmetricPublicKey<V3>>,
+}
+
+impl TrustedKeys {
+  /// Load trusted public keys from the given files. Each file is
+  /// expected to contain a single hex encoded P-384 (PASETO v3)
+  /// public key, and is
+  /// identified by its file stem (i.e., the key id clients reference
+  /// via the `sub` claim is the file name without its extension).
+  pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+    let mut keys = HashMap::new();
+    for path in paths {
+      let path = path.as_ref();
+      let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .with_context(|| format!("failed to derive key id from {}", path.display()))?
+        .to_string();
+
+      let raw = read_to_string(path)
+        .with_context(|| format!("failed to read trusted key {}", path.display()))?;
+      let bytes = hex::decode(raw.trim())
+        .with_context(|| format!("trusted key {} is not valid hex", path.display()))?;
+      let key = AsymmetricPublicKey::<V3>::from(&bytes)
+        .with_context(|| format!("{} is not a valid P-384 public key", path.display()))?;
+
+      keys.insert(kid, key);
+    }
+
+    Ok(Self { keys })
+  }
+}
+
+/// The context a token must be bound to via PASETO's implicit
+/// assertion, in order to prevent it from being replayed against a
+/// different registry or endpoint.
+pub struct Binding<'a> {
+  /// The externally visible address of this registry.
+  pub registry: &'a str,
+  /// The name of the endpoint being invoked, e.g. `v1/crates/new`.
+  pub endpoint: &'a str,
+  /// For publish requests, the crate name and version being
+  /// uploaded, further narrowing what the token authorizes.
+  pub target: Option<(&'a str, &'a str)>,
+}
+
+impl Binding<'_> {
+  /// Render this binding as an unambiguous byte string suitable for
+  /// use as a PASETO implicit assertion.
+  ///
+  /// We deliberately do not join the fields with a separator such as
+  /// `|`: crate names and versions are not restricted to a safe
+  /// charset anywhere in this server, so a naive join would let a
+  /// token scoped to e.g. name `a|b`, version `c` verify equally well
+  /// for name `a`, version `b|c`. Serializing the tuple as JSON keeps
+  /// each field's length self-describing and avoids that ambiguity.
+  fn implicit_assertion(&self) -> String {
+    serde_json::to_string(&(self.registry, self.endpoint, self.target))
+      .expect("serializing a tuple of strings can't fail")
+  }
+}
+
+/// Peek at the unverified claims a token carries, without checking
+/// its signature. For a `.public` token the payload is the plaintext
+/// claims, so this is safe to do before we even know which key to
+/// verify against.
+fn peek_claims(untrusted: &UntrustedToken<Public, V3>) -> Option<Claims> {
+  Claims::from_bytes(untrusted.untrusted_payload()).ok()
+}
+
+/// Verify that `token` is a valid, unexpired PASETO v3 public token
+/// signed by one of `keys`, and bound to `binding`. On success,
+/// returns the key id (`sub` claim) it verified against -- since that
+/// claim is now known to be genuine, callers may use it to identify
+/// the client, e.g. for rate limiting.
+pub fn verify(keys: &TrustedKeys, token: &str, binding: &Binding<'_>) -> Result<String> {
+  // We cannot look up the correct verification key before having
+  // parsed (but not yet verified) the token's `sub` claim, so peek at
+  // it first.
+  let untrusted = UntrustedToken::<Public, V3>::try_from(token)
+    .with_context(|| "failed to parse PASETO token")?;
+  let kid = peek_claims(&untrusted)
+    .with_context(|| "token payload does not contain valid claims")?
+    .get_claim("sub")
+    .and_then(|sub| sub.as_str())
+    .with_context(|| "token is missing a `sub` claim")?
+    .to_string();
+
+  let key = keys
+    .keys
+    .get(&kid)
+    .with_context(|| format!("token references unknown key id `{}`", kid))?;
+
+  let implicit_assertion = binding.implicit_assertion();
+  let trusted = PublicToken::verify(key, &untrusted, None, Some(implicit_assertion.as_bytes()))
+    .map_err(Error::from)
+    .with_context(|| "failed to verify PASETO token")?;
+
+  // `PublicToken::verify` only authenticates the token; it does not
+  // parse or validate its claims (that's a `v3`-specific wrinkle --
+  // the convenience wrapper that does both only exists for `v4` in
+  // this crate), so we parse the now-trusted payload ourselves.
+  let claims =
+    Claims::from_string(trusted.payload()).with_context(|| "verified token carries no claims")?;
+
+  // `ClaimsValidationRules::new()` already validates that `exp` is
+  // present and not in the past, and that `nbf`/`iat` are present and
+  // not in the future.
+  ClaimsValidationRules::new()
+    .validate_claims(&claims)
+    .map_err(Error::from)
+    .with_context(|| "token claims failed validation")?;
+
+  Ok(kid)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pasetors::keys::AsymmetricKeyPair;
+  use pasetors::keys::Generate as _;
+
+  fn trusted(kid: &str, pair: &AsymmetricKeyPair<V3>) -> TrustedKeys {
+    let mut keys = HashMap::new();
+    keys.insert(kid.to_string(), pair.public.clone());
+    TrustedKeys { keys }
+  }
+
+  fn binding() -> Binding<'static> {
+    Binding {
+      registry: "http://localhost:8080",
+      endpoint: "v1/crates/new",
+      target: Some(("demo", "0.1.0")),
+    }
+  }
+
+  #[test]
+  fn forged_token_is_rejected() {
+    let pair = AsymmetricKeyPair::<V3>::generate().unwrap();
+    // A key the attacker controls, but that the registry does not
+    // trust.
+    let forged = AsymmetricKeyPair::<V3>::generate().unwrap();
+    let keys = trusted("test-key", &pair);
+    let binding = binding();
+
+    let mut claims = Claims::new().unwrap();
+    claims.subject("test-key").unwrap();
+    let token = PublicToken::sign(
+      &forged.secret,
+      claims.to_string().unwrap().as_bytes(),
+      None,
+      Some(binding.implicit_assertion().as_bytes()),
+    )
+    .unwrap();
+
+    assert!(verify(&keys, &token, &binding).is_err());
+  }
+
+  #[test]
+  fn garbage_token_is_rejected() {
+    let pair = AsymmetricKeyPair::<V3>::generate().unwrap();
+    let keys = trusted("test-key", &pair);
+    let binding = binding();
+
+    assert!(verify(&keys, "not-a-paseto-token", &binding).is_err());
+  }
+
+  #[test]
+  fn expired_token_is_rejected() {
+    let pair = AsymmetricKeyPair::<V3>::generate().unwrap();
+    let keys = trusted("test-key", &pair);
+    let binding = binding();
+
+    let mut claims = Claims::new().unwrap();
+    claims.subject("test-key").unwrap();
+    claims.expiration("2000-01-01T00:00:00+00:00").unwrap();
+    let token = PublicToken::sign(
+      &pair.secret,
+      claims.to_string().unwrap().as_bytes(),
+      None,
+      Some(binding.implicit_assertion().as_bytes()),
+    )
+    .unwrap();
+
+    assert!(verify(&keys, &token, &binding).is_err());
+  }
+
+  #[test]
+  fn valid_token_is_accepted() {
+    let pair = AsymmetricKeyPair::<V3>::generate().unwrap();
+    let keys = trusted("test-key", &pair);
+    let binding = binding();
+
+    let mut claims = Claims::new().unwrap();
+    claims.subject("test-key").unwrap();
+    let token = PublicToken::sign(
+      &pair.secret,
+      claims.to_string().unwrap().as_bytes(),
+      None,
+      Some(binding.implicit_assertion().as_bytes()),
+    )
+    .unwrap();
+
+    assert_eq!(verify(&keys, &token, &binding).unwrap(), "test-key");
+  }
+}
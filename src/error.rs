@@ -0,0 +1,57 @@
+// Copyright (C) 2020 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A dedicated error type for conditions the registry recognizes and
+//! wants to report in a structured fashion, as opposed to an opaque
+//! `anyhow::Error` string.
+//!
+//! Call sites are free to keep using `anyhow::Result` and `?` as
+//! before; because [`Error`] implements [`std::error::Error`] it
+//! converts into an `anyhow::Error` automatically, while still being
+//! recoverable via [`anyhow::Error::downcast_ref`] at the point where
+//! we turn a failure into a response.
+
+use http::StatusCode;
+
+use thiserror::Error as ThisError;
+
+
+/// An error describing why a registry request could not be
+/// satisfied.
+#[derive(Debug, ThisError)]
+pub enum Error {
+  /// An uploaded `.crate` file exceeded our configured size limit.
+  #[error("crate file exceeds the maximum allowed size")]
+  CrateTooLarge,
+  /// The metadata cargo sent along with a publish request could not
+  /// be parsed or was missing required fields.
+  #[error("crate manifest is invalid")]
+  InvalidManifest(#[source] anyhow::Error),
+  /// A publish request tried to upload a name/version combination
+  /// that already exists in the index.
+  #[error("{name}-{version} already exists")]
+  VersionExists { name: String, version: String },
+  /// Reading or writing a part of the on-disk index failed.
+  #[error("failed to access crate index")]
+  IndexIo(#[source] anyhow::Error),
+  /// The requested crate or version is not present in the index.
+  #[error("{name}-{version} could not be found")]
+  NotFound { name: String, version: String },
+}
+
+impl Error {
+  /// The conceptual HTTP status this error corresponds to. The
+  /// cargo-facing endpoints always respond with `200 OK` and an error
+  /// array (cargo does not parse a status for most endpoints) and do
+  /// not consult this mapping; the admin API's read endpoints respond
+  /// with it.
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Self::CrateTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+      Self::InvalidManifest(..) => StatusCode::BAD_REQUEST,
+      Self::VersionExists { .. } => StatusCode::CONFLICT,
+      Self::IndexIo(..) => StatusCode::INTERNAL_SERVER_ERROR,
+      Self::NotFound { .. } => StatusCode::NOT_FOUND,
+    }
+  }
+}
@@ -0,0 +1,340 @@
+// Copyright (C) 2020 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Functionality for reading & writing the crate index backing this
+//! registry.
+//!
+//! The index is laid out on disk exactly the way cargo expects a Git
+//! based registry index to look: one newline delimited JSON file per
+//! crate, located at a path derived from the crate's name (see
+//! [here][] for the exact scheme), plus a `config.json` at the root
+//! describing where crates can be downloaded from and where the
+//! "API" (i.e., this server) lives.
+//!
+//! [here]: https://doc.rust-lang.org/cargo/reference/registries.html#index-format
+
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::read_to_string;
+use std::fs::remove_file;
+use std::fs::write;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::from_str;
+use serde_json::to_string;
+
+
+/// The name of the file we use to persist the port we ended up
+/// listening on, so that subsequent invocations with an ephemeral
+/// port can reuse it across restarts.
+const PORT_FILE: &str = ".port";
+
+/// The name of the directory, relative to the registry root, that
+/// holds the index files.
+const INDEX_DIR: &str = "index";
+
+/// The name of the directory, relative to the registry root, that
+/// holds the uploaded `.crate` tarballs.
+const CRATES_DIR: &str = "crates";
+
+/// The `config.json` cargo retrieves to discover how to talk to this
+/// registry.
+#[derive(Debug, Deserialize, Serialize)]
+struct Config {
+  /// The template used for downloading crates.
+  dl: String,
+  /// The URL of the API this registry exposes.
+  api: String,
+  /// Whether cargo needs to send a PASETO token along with mutating
+  /// requests.
+  #[serde(rename = "auth-required")]
+  auth_required: bool,
+}
+
+/// A single version as it is represented in a crate's index file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexEntry {
+  pub name: String,
+  pub vers: String,
+  #[serde(default)]
+  pub deps: serde_json::Value,
+  pub cksum: String,
+  #[serde(default)]
+  pub features: serde_json::Value,
+  pub yanked: bool,
+  #[serde(default)]
+  pub links: Option<String>,
+}
+
+/// The object used to interact with the on-disk crate index.
+#[derive(Debug)]
+pub struct Index {
+  /// The root directory of the registry.
+  root: PathBuf,
+}
+
+impl Index {
+  /// Create (or open) the index rooted at `root`, serving at `addr`.
+  /// `auth_required` controls whether we advertise to cargo that
+  /// mutating requests need to carry a PASETO token.
+  pub fn new(root: &Path, addr: &SocketAddr, auth_required: bool) -> Result<Self> {
+    create_dir_all(root.join(INDEX_DIR))
+      .with_context(|| format!("failed to create index directory in {}", root.display()))?;
+    create_dir_all(root.join(CRATES_DIR))
+      .with_context(|| format!("failed to create crates directory in {}", root.display()))?;
+
+    let slf = Self {
+      root: root.to_path_buf(),
+    };
+
+    slf.write_port(addr.port())?;
+    slf.write_config(addr, auth_required)?;
+    Ok(slf)
+  }
+
+  /// Persist the port we ended up binding to, so that a future
+  /// invocation using an ephemeral port can attempt to reuse it.
+  fn write_port(&self, port: u16) -> Result<()> {
+    write(self.root.join(PORT_FILE), port.to_string())
+      .with_context(|| format!("failed to persist listening port {}", port))
+  }
+
+  /// Try to read back a previously persisted port.
+  pub fn try_read_port(root: &Path) -> Result<u16> {
+    let content = read_to_string(root.join(PORT_FILE))
+      .with_context(|| format!("failed to read {}", root.join(PORT_FILE).display()))?;
+    content
+      .trim()
+      .parse::<u16>()
+      .with_context(|| format!("failed to parse persisted port `{}`", content))
+  }
+
+  /// Write out the `config.json` cargo uses to discover this
+  /// registry's download and API endpoints.
+  fn write_config(&self, addr: &SocketAddr, auth_required: bool) -> Result<()> {
+    let config = Config {
+      dl: format!("http://{}/api/v1/crates/{{crate}}/{{version}}/download", addr),
+      api: format!("http://{}", addr),
+      auth_required,
+    };
+    let encoded = to_string(&config).with_context(|| "failed to encode config.json")?;
+    write(self.root.join(INDEX_DIR).join("config.json"), encoded)
+      .with_context(|| "failed to write config.json")
+  }
+
+  /// Compute the path, relative to the index directory, at which the
+  /// index file for `name` lives, following cargo's prefix scheme.
+  fn rel_index_path(name: &str) -> PathBuf {
+    let lower = name.to_lowercase();
+    match lower.len() {
+      1 => PathBuf::from("1").join(&lower),
+      2 => PathBuf::from("2").join(&lower),
+      3 => PathBuf::from("3").join(&lower[..1]).join(&lower),
+      _ => PathBuf::from(&lower[..2]).join(&lower[2..4]).join(&lower),
+    }
+  }
+
+  /// Retrieve the absolute path of the index file for `name`.
+  pub fn index_path(&self, name: &str) -> PathBuf {
+    self.root.join(INDEX_DIR).join(Self::rel_index_path(name))
+  }
+
+  /// Retrieve the absolute path at which the `.crate` tarball for
+  /// `name`/`version` is (or would be) stored.
+  ///
+  /// `name` is lowercased, matching `index_path`, so that the two
+  /// stay consistent regardless of the case a caller looks a crate up
+  /// by.
+  pub fn crate_path(&self, name: &str, version: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    self
+      .root
+      .join(CRATES_DIR)
+      .join(&name)
+      .join(version)
+      .join(format!("{}-{}.crate", name, version))
+  }
+
+  /// Append `entry` to the index file for its crate, creating the
+  /// file (and any necessary prefix directories) if necessary.
+  pub fn add_entry(&mut self, entry: &IndexEntry) -> Result<()> {
+    let path = self.index_path(&entry.name);
+    let parent = path
+      .parent()
+      .with_context(|| format!("index path {} has no parent", path.display()))?;
+    create_dir_all(parent)
+      .with_context(|| format!("failed to create index directory {}", parent.display()))?;
+
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&path)
+      .with_context(|| format!("failed to open index file {}", path.display()))?;
+
+    let line =
+      to_string(entry).with_context(|| format!("failed to encode index entry for {}", entry.name))?;
+    writeln!(file, "{}", line)
+      .with_context(|| format!("failed to append entry to index file {}", path.display()))?;
+
+    Ok(())
+  }
+
+  /// Read back all entries present in the index file for `name`. An
+  /// absent index file (i.e., a crate we have never seen a version
+  /// of) is treated as having no entries rather than as an error.
+  pub fn entries(&self, name: &str) -> Result<Vec<IndexEntry>> {
+    let path = self.index_path(name);
+    let content = match read_to_string(&path) {
+      Ok(content) => content,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(err) => {
+        return Err(crate::error::Error::IndexIo(
+          Error::new(err).context(format!("failed to read index file {}", path.display())),
+        )
+        .into())
+      },
+    };
+
+    content
+      .lines()
+      .filter(|line| !line.is_empty())
+      .map(|line| {
+        from_str::<IndexEntry>(line)
+          .with_context(|| format!("failed to parse index entry `{}`", line))
+      })
+      .collect()
+  }
+
+  /// Check whether `version` of `name` is already present in the
+  /// index.
+  pub fn contains_version(&self, name: &str, version: &str) -> Result<bool> {
+    Ok(self.entries(name)?.iter().any(|entry| entry.vers == version))
+  }
+
+  /// Rewrite the index file for `name`, replacing its entries with
+  /// `entries`.
+  fn write_entries(&self, name: &str, entries: &[IndexEntry]) -> Result<()> {
+    let path = self.index_path(name);
+    let mut content = String::new();
+    for entry in entries {
+      let line = to_string(entry)
+        .with_context(|| format!("failed to encode index entry for {}", entry.name))?;
+      content.push_str(&line);
+      content.push('\n');
+    }
+
+    write(&path, content).with_context(|| format!("failed to write index file {}", path.display()))
+  }
+
+  /// Flip the `yanked` flag of `version` of crate `name`, failing if
+  /// the version does not exist or is already in the desired state.
+  pub fn set_yanked(&mut self, name: &str, version: &str, yanked: bool) -> Result<()> {
+    let mut entries = self.entries(name)?;
+    let entry = entries
+      .iter_mut()
+      .find(|entry| entry.vers == version)
+      .ok_or_else(|| crate::error::Error::NotFound {
+        name: name.to_string(),
+        version: version.to_string(),
+      })?;
+
+    anyhow::ensure!(
+      entry.yanked != yanked,
+      "{}-{} is already {}",
+      name,
+      version,
+      if yanked { "yanked" } else { "unyanked" }
+    );
+
+    entry.yanked = yanked;
+    self.write_entries(name, &entries)
+  }
+
+  /// List the names of all crates currently present in the index, by
+  /// walking the index directory tree. Used by the admin API, which
+  /// otherwise has no other way to enumerate crates short of reading
+  /// files directly.
+  pub fn list_crates(&self) -> Result<Vec<String>> {
+    fn walk(dir: &Path, names: &mut Vec<String>) -> Result<()> {
+      for entry in
+        read_dir(dir).with_context(|| format!("failed to read directory {}", dir.display()))?
+      {
+        let entry = entry.with_context(|| format!("failed to read entry in {}", dir.display()))?;
+        let path = entry.path();
+        if path.is_dir() {
+          walk(&path, names)?;
+        } else if path.file_name().and_then(|name| name.to_str()) != Some("config.json") {
+          if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            names.push(name.to_string());
+          }
+        }
+      }
+      Ok(())
+    }
+
+    let mut names = Vec::new();
+    let index_dir = self.root.join(INDEX_DIR);
+    if index_dir.is_dir() {
+      walk(&index_dir, &mut names)?;
+    }
+    names.sort();
+    Ok(names)
+  }
+
+  /// Hard-remove `version` of crate `name`: delete its tarball and
+  /// its line in the index, as opposed to [`Index::set_yanked`] which
+  /// merely flags it. If no versions of the crate remain afterwards,
+  /// the index file itself is removed as well.
+  pub fn remove_version(&mut self, name: &str, version: &str) -> Result<()> {
+    let mut entries = self.entries(name)?;
+    let len_before = entries.len();
+    entries.retain(|entry| entry.vers != version);
+
+    anyhow::ensure!(
+      entries.len() != len_before,
+      crate::error::Error::NotFound {
+        name: name.to_string(),
+        version: version.to_string(),
+      }
+    );
+
+    let crate_path = self.crate_path(name, version);
+    if crate_path.exists() {
+      remove_file(&crate_path)
+        .with_context(|| format!("failed to remove crate file {}", crate_path.display()))?;
+    }
+
+    if entries.is_empty() {
+      let index_path = self.index_path(name);
+      remove_file(&index_path)
+        .with_context(|| format!("failed to remove index file {}", index_path.display()))
+    } else {
+      self.write_entries(name, &entries)
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rel_index_paths() {
+    assert_eq!(Index::rel_index_path("a"), PathBuf::from("1/a"));
+    assert_eq!(Index::rel_index_path("ab"), PathBuf::from("2/ab"));
+    assert_eq!(Index::rel_index_path("abc"), PathBuf::from("3/a/abc"));
+    assert_eq!(Index::rel_index_path("abcd"), PathBuf::from("ab/cd/abcd"));
+    assert_eq!(Index::rel_index_path("abcde"), PathBuf::from("ab/cd/abcde"));
+  }
+}
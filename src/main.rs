@@ -9,17 +9,22 @@
 //!
 //! [here]: https://doc.rust-lang.org/cargo/reference/registries.html
 
+mod auth;
+mod error;
 mod index;
 mod publish;
+mod ratelimit;
 
-use std::fmt::Display;
+use std::fs::read;
 use std::io::stdout;
 use std::io::Write as _;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::SystemTime;
 
 use anyhow::Context as _;
 use anyhow::Error;
@@ -29,6 +34,8 @@ use http::StatusCode;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::to_string;
+use sha2::Digest as _;
+use sha2::Sha256;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
@@ -52,6 +59,34 @@ pub struct Args {
   /// ephemeral port.
   #[structopt(short, long, default_value = "127.0.0.1:0")]
   addr: SocketAddr,
+  /// Serve the index over cargo's sparse HTTP protocol via `GET
+  /// /index/...` routes. The index is kept as plain files on disk
+  /// either way; it is not a Git repository.
+  #[structopt(long)]
+  sparse: bool,
+  /// A file containing a trusted, hex encoded P-384 public key used
+  /// to verify PASETO tokens on publish/yank/unyank requests. May be
+  /// given multiple times. When no key is provided, requests are not
+  /// authenticated at all.
+  #[structopt(long = "trusted-key", parse(from_os_str))]
+  trusted_keys: Vec<PathBuf>,
+  /// The number of publish requests per second a single client (IP,
+  /// or authenticated key id) is allowed to make, on average.
+  #[structopt(long, default_value = "1")]
+  rate_limit: f64,
+  /// The number of publish requests a single client may burst to
+  /// before being throttled.
+  #[structopt(long, default_value = "5")]
+  rate_limit_burst: f64,
+  /// Expose an admin API (`GET /admin/crates`, `GET
+  /// /admin/crates/{name}`, `DELETE /admin/crates/{name}/{version}`)
+  /// for inspecting and garbage-collecting the registry.
+  #[structopt(long)]
+  admin: bool,
+  /// Serve the admin API on a separate address instead of alongside
+  /// the regular registry routes. Implies `--admin`.
+  #[structopt(long)]
+  admin_addr: Option<SocketAddr>,
   /// Increase verbosity (can be supplied multiple times).
   #[structopt(short = "v", long = "verbose", global = true, parse(from_occurrences))]
   verbosity: usize,
@@ -81,19 +116,146 @@ impl From<Error> for RegistryErrors {
   }
 }
 
-fn encode_fallback_error<E>(err: E) -> String
-where
-  E: Display,
-{
-  // We are missing proper escaping here, so this conversion should
-  // really be seen as last resort.
-  format!(
-    r#"{{"errors":[
-    {{"detail":"failed to convert internal error to JSON"}},
-    {{"detail":"{}"}}
-  ]}}"#,
-    err
-  )
+/// Serve a single file out of the on-disk index, the way cargo's
+/// sparse registry protocol expects: as the raw newline delimited
+/// JSON, with an `ETag` and `Last-Modified` header so that cargo can
+/// perform conditional requests.
+fn serve_index_file(root: &Path, path: &str, if_none_match: Option<String>) -> warp::reply::Response {
+  use warp::http::Response;
+
+  // Guard against the tail containing path traversal components; we
+  // never expect cargo to send us anything like that, but better
+  // safe than sorry.
+  if path.split('/').any(|component| component == ".." || component == ".") {
+    return Response::builder()
+      .status(StatusCode::BAD_REQUEST)
+      .body(Vec::new().into())
+      .unwrap();
+  }
+
+  let file = root.join("index").join(path);
+  let content = match read(&file) {
+    Ok(content) => content,
+    Err(_) => {
+      return Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Vec::new().into())
+        .unwrap()
+    },
+  };
+
+  let etag = format!("\"{:x}\"", Sha256::digest(&content));
+  if if_none_match.as_deref() == Some(etag.as_str()) {
+    return Response::builder()
+      .status(StatusCode::NOT_MODIFIED)
+      .header("ETag", etag)
+      .body(Vec::new().into())
+      .unwrap();
+  }
+
+  let last_modified = file
+    .metadata()
+    .and_then(|metadata| metadata.modified())
+    .unwrap_or_else(|_| SystemTime::now());
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header("Content-Type", "text/plain; charset=utf-8")
+    .header("ETag", etag)
+    .header("Last-Modified", httpdate::fmt_http_date(last_modified))
+    .body(content.into())
+    .unwrap()
+}
+
+/// Parse a single `Range: bytes=<start>-<end>` header value, returning
+/// the inclusive `(start, end)` byte range it describes, or `None` if
+/// the range is malformed or not satisfiable for a body of `len`
+/// bytes. We only support a single range, which is all cargo (and
+/// most CDNs) ever send.
+fn parse_byte_range(range: &str, len: usize) -> Option<(usize, usize)> {
+  let range = range.strip_prefix("bytes=")?;
+  // Reject multiple ranges; we only support the common single-range
+  // case.
+  if range.contains(',') {
+    return None;
+  }
+
+  let (start, end) = range.split_once('-')?;
+  let (start, end) = if start.is_empty() {
+    // A suffix range, e.g. `bytes=-500`, meaning the last 500 bytes.
+    let suffix_len = end.parse::<usize>().ok()?;
+    let start = len.saturating_sub(suffix_len);
+    (start, len.checked_sub(1)?)
+  } else {
+    let start = start.parse::<usize>().ok()?;
+    let end = if end.is_empty() {
+      len.checked_sub(1)?
+    } else {
+      end.parse::<usize>().ok()?
+    };
+    (start, end)
+  };
+
+  if start > end || start >= len {
+    return None;
+  }
+
+  Some((start, end.min(len - 1)))
+}
+
+/// Serve the `.crate` tarball for `name`/`version`, as stored by
+/// [`publish::publish_crate`], optionally honoring a `Range` header
+/// for resumable/partial downloads.
+fn download_crate(
+  index: &index::Index,
+  name: &str,
+  version: &str,
+  range: Option<String>,
+) -> warp::reply::Response {
+  use warp::http::Response;
+
+  let path = index.crate_path(name, version);
+  let content = match read(&path) {
+    Ok(content) => content,
+    Err(_) => {
+      let errors = RegistryErrors {
+        errors: vec![RegistryError {
+          detail: format!("crate {}-{} not found", name, version),
+        }],
+      };
+      let body = to_string(&errors).expect("failed to encode registry errors as JSON");
+      return Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(body.into_bytes().into())
+        .unwrap();
+    },
+  };
+
+  if let Some(range) = range {
+    return match parse_byte_range(&range, content.len()) {
+      Some((start, end)) => Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Type", "application/x-tar")
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Range", format!("bytes {}-{}/{}", start, end, content.len()))
+        .header("Content-Length", end - start + 1)
+        .body(content[start..=end].to_vec().into())
+        .unwrap(),
+      None => Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header("Content-Range", format!("bytes */{}", content.len()))
+        .body(Vec::new().into())
+        .unwrap(),
+    };
+  }
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header("Content-Type", "application/x-tar")
+    .header("Accept-Ranges", "bytes")
+    .header("Content-Length", content.len())
+    .body(content.into())
+    .unwrap()
 }
 
 /// Convert a result back into a response.
@@ -107,7 +269,7 @@ async fn response(result: Result<()>) -> Result<impl warp::Reply, warp::Rejectio
       error!("request status: error: {:#}", err);
 
       let errors = RegistryErrors::from(err);
-      to_string(&errors).unwrap_or_else(encode_fallback_error)
+      to_string(&errors).expect("failed to encode registry errors as JSON")
     },
   };
   // Registries always respond with OK and use the JSON error array to
@@ -116,6 +278,189 @@ async fn response(result: Result<()>) -> Result<impl warp::Reply, warp::Rejectio
   Ok(reply)
 }
 
+/// A rejection emitted when a client has exceeded its rate limit. The
+/// wrapped duration is how long the client should wait before trying
+/// again.
+#[derive(Debug)]
+struct RateLimited(std::time::Duration);
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Apply the rate limiter to a request, keyed by the client's
+/// *verified* key id if authentication is enabled and the request
+/// carried a token that actually verified, or by its address
+/// otherwise. `kid` must come from [`auth::verify`], never from an
+/// unverified claim, or a client could evade rate limiting entirely by
+/// rotating a bogus `sub` on every request.
+fn check_rate_limit(
+  limiter: &ratelimit::Limiter,
+  addr: Option<SocketAddr>,
+  kid: Option<String>,
+) -> Result<(), warp::Rejection> {
+  let key = match kid {
+    Some(kid) => ratelimit::Key::KeyId(kid),
+    None => {
+      let addr = addr.with_context(|| "request has no discernible client address").map_err(|_| {
+        warp::reject::custom(RateLimited(std::time::Duration::from_secs(1)))
+      })?;
+      ratelimit::Key::Addr(addr.ip())
+    },
+  };
+
+  limiter
+    .check(key)
+    .map_err(|retry_after| warp::reject::custom(RateLimited(retry_after)))
+}
+
+/// Turn a [`RateLimited`] rejection into the standard `RegistryErrors`
+/// JSON body, with a `Retry-After` header so well behaved clients back
+/// off.
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+  if let Some(RateLimited(retry_after)) = err.find() {
+    let errors = RegistryErrors {
+      errors: vec![RegistryError {
+        detail: "rate limit exceeded".to_string(),
+      }],
+    };
+    let body = to_string(&errors).expect("failed to encode registry errors as JSON");
+    let reply = warp::reply::with_status(body, StatusCode::TOO_MANY_REQUESTS);
+    let reply = warp::reply::with_header(reply, "Retry-After", retry_after.as_secs().max(1));
+    return Ok(reply);
+  }
+
+  Err(err)
+}
+
+/// Verify the `Authorization` header of a mutating request against
+/// `keys`, if authentication is enabled. `target` additionally binds
+/// a publish request to the specific crate name/version being
+/// uploaded. On success, returns the verified key id, or `None` if
+/// authentication is disabled.
+fn authorize(
+  keys: &Option<auth::TrustedKeys>,
+  registry: &str,
+  endpoint: &str,
+  target: Option<(&str, &str)>,
+  header: Option<String>,
+) -> Result<Option<String>> {
+  let keys = match keys {
+    Some(keys) => keys,
+    // Authentication is disabled; nothing to do.
+    None => return Ok(None),
+  };
+
+  let header = header.with_context(|| "request is missing an Authorization header")?;
+  let token = header.strip_prefix("Bearer ").unwrap_or(&header);
+  let binding = auth::Binding {
+    registry,
+    endpoint,
+    target,
+  };
+  auth::verify(keys, token, &binding).map(Some)
+}
+
+/// Convert a result back into a response the way cargo expects it for
+/// the yank/unyank endpoints: `{"ok":true}` on success, or the usual
+/// JSON error array on failure.
+async fn response_ok(result: Result<()>) -> Result<impl warp::Reply, warp::Rejection> {
+  let body = match result {
+    Ok(()) => {
+      info!("request status: success");
+      r#"{"ok":true}"#.to_string()
+    },
+    Err(err) => {
+      error!("request status: error: {:#}", err);
+
+      let errors = RegistryErrors::from(err);
+      to_string(&errors).expect("failed to encode registry errors as JSON")
+    },
+  };
+  let reply = warp::reply::with_status(body, StatusCode::OK);
+  Ok(reply)
+}
+
+/// A single version of a crate as exposed by the admin API.
+#[derive(Debug, Serialize)]
+struct AdminVersionSummary {
+  vers: String,
+  yanked: bool,
+}
+
+/// A crate and all its versions as exposed by the admin API.
+#[derive(Debug, Serialize)]
+struct AdminCrateSummary {
+  name: String,
+  versions: Vec<AdminVersionSummary>,
+}
+
+/// Summarize a single crate's versions, failing if it has none (i.e.,
+/// is not actually present in the index).
+fn admin_crate_summary(index: &index::Index, name: &str) -> Result<AdminCrateSummary> {
+  let entries = index.entries(name)?;
+  anyhow::ensure!(
+    !entries.is_empty(),
+    error::Error::NotFound {
+      name: name.to_string(),
+      version: "*".to_string(),
+    }
+  );
+
+  Ok(AdminCrateSummary {
+    // `name` is only a lookup key and may disagree in case with what
+    // was actually published; report the canonical name the index
+    // itself recorded instead of echoing it back.
+    name: entries[0].name.clone(),
+    versions: entries
+      .into_iter()
+      .map(|entry| AdminVersionSummary {
+        vers: entry.vers,
+        yanked: entry.yanked,
+      })
+      .collect(),
+  })
+}
+
+/// Summarize every crate currently present in the index.
+fn admin_list_crates(index: &index::Index) -> Result<Vec<AdminCrateSummary>> {
+  index
+    .list_crates()?
+    .into_iter()
+    .map(|name| admin_crate_summary(index, &name))
+    .collect()
+}
+
+/// Convert a result carrying a JSON payload into a response, the way
+/// the admin API's read endpoints expect it: the encoded payload on
+/// success, or the usual JSON error array on failure. Unlike
+/// [`response`] and [`response_ok`], which always answer with `200
+/// OK` to match what cargo itself expects, the admin API is our own
+/// surface, so we honor [`error::Error::status`] instead.
+async fn admin_response<T>(result: Result<T>) -> Result<impl warp::Reply, warp::Rejection>
+where
+  T: Serialize,
+{
+  let (body, status) = match result {
+    Ok(value) => (
+      to_string(&value).expect("failed to encode admin response as JSON"),
+      StatusCode::OK,
+    ),
+    Err(err) => {
+      error!("request status: error: {:#}", err);
+
+      let status = err
+        .downcast_ref::<error::Error>()
+        .map(error::Error::status)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+      let errors = RegistryErrors::from(err);
+      (
+        to_string(&errors).expect("failed to encode registry errors as JSON"),
+        status,
+      )
+    },
+  };
+  Ok(warp::reply::with_status(body, status))
+}
+
 fn run() -> Result<()> {
   let args = Args::from_args_safe()?;
   // Unfortunately because of how we have to define our routes in order
@@ -124,26 +469,227 @@ fn run() -> Result<()> {
   // index we have a circular dependency that we can only resolve by use
   // of an `Option`. *sadpanda*
   let shared = Arc::new(Mutex::new(Option::<index::Index>::None));
-  let copy = shared.clone();
+  let auth = if args.trusted_keys.is_empty() {
+    None
+  } else {
+    Some(auth::TrustedKeys::load(&args.trusted_keys).with_context(|| "failed to load trusted keys")?)
+  };
+  let auth = Arc::new(auth);
+  // The registry's own notion of its address, used to bind tokens to
+  // this particular server. We deliberately use the configured
+  // address rather than the (possibly ephemeral) one we actually end
+  // up bound to, so that this value is available before we bind.
+  let registry = format!("http://{}", args.addr);
+  let limiter = Arc::new(ratelimit::Limiter::new(ratelimit::Limits {
+    rate: args.rate_limit,
+    burst: args.rate_limit_burst,
+  }));
 
+  let copy = shared.clone();
+  let auth_copy = auth.clone();
+  let registry_copy = registry.clone();
+  let limiter_copy = limiter.clone();
   let publish = warp::put()
     .and(warp::path("api"))
     .and(warp::path("v1"))
     .and(warp::path("crates"))
     .and(warp::path("new"))
     .and(warp::path::end())
+    .and(warp::addr::remote())
+    .and(warp::header::optional::<String>("authorization"))
     .and(warp::body::bytes())
     // We cap total body size to 2 MiB to have some upper bound. I
     // believe that's what crates.io does as well.
     .and(warp::body::content_length_limit(2 * 1024 * 1024))
-    .map(move |body| {
-      let mut index = copy.lock().unwrap();
-      let mut index = index.as_mut().unwrap();
-      publish::publish_crate(body, &mut index)
+    .and_then(move |addr: Option<SocketAddr>, authz: Option<String>, body: bytes::Bytes| {
+      let auth_copy = auth_copy.clone();
+      let registry_copy = registry_copy.clone();
+      let limiter = limiter_copy.clone();
+      async move {
+        // Authorize using the crate name/version the body claims
+        // *before* consulting the rate limiter, so that -- when
+        // authentication is enabled -- we only ever key the limiter
+        // by a key id PASETO has actually verified, never by an
+        // unverified `sub` claim; trusting the latter would let a
+        // client evade rate limiting entirely by rotating a bogus one
+        // on every request.
+        let outcome = publish::peek_metadata(&body).and_then(|(name, version)| {
+          authorize(
+            &auth_copy,
+            &registry_copy,
+            "v1/crates/new",
+            Some((&name, &version)),
+            authz,
+          )
+        });
+
+        let kid = match outcome {
+          Ok(kid) => kid,
+          Err(err) => return Ok((Err(err), body)),
+        };
+
+        check_rate_limit(&limiter, addr, kid).map(|()| (Ok(()), body))
+      }
+    })
+    .map(move |(outcome, body): (Result<()>, bytes::Bytes)| {
+      outcome.and_then(|()| {
+        let mut index = copy.lock().unwrap();
+        let index = index.as_mut().unwrap();
+        publish::publish_crate(body, index)
+      })
     })
     .and_then(response)
     .with(warp::trace::request());
 
+  let copy = shared.clone();
+  let download = warp::get()
+    .and(warp::path("api"))
+    .and(warp::path("v1"))
+    .and(warp::path("crates"))
+    .and(warp::path::param())
+    .and(warp::path::param())
+    .and(warp::path("download"))
+    .and(warp::path::end())
+    .and(warp::header::optional::<String>("range"))
+    .map(move |name: String, version: String, range: Option<String>| {
+      let index = copy.lock().unwrap();
+      let index = index.as_ref().unwrap();
+      download_crate(index, &name, &version, range)
+    })
+    .with(warp::trace::request());
+
+  let copy = shared.clone();
+  let auth_copy = auth.clone();
+  let registry_copy = registry.clone();
+  let yank = warp::delete()
+    .and(warp::path("api"))
+    .and(warp::path("v1"))
+    .and(warp::path("crates"))
+    .and(warp::path::param())
+    .and(warp::path::param())
+    .and(warp::path("yank"))
+    .and(warp::path::end())
+    .and(warp::header::optional::<String>("authorization"))
+    .map(move |name: String, version: String, authz| {
+      authorize(&auth_copy, &registry_copy, "v1/crates/yank", None, authz)?;
+      let mut index = copy.lock().unwrap();
+      let index = index.as_mut().unwrap();
+      publish::yank_crate(index, &name, &version)
+    })
+    .and_then(response_ok)
+    .with(warp::trace::request());
+
+  let copy = shared.clone();
+  let auth_copy = auth.clone();
+  let registry_copy = registry.clone();
+  let unyank = warp::put()
+    .and(warp::path("api"))
+    .and(warp::path("v1"))
+    .and(warp::path("crates"))
+    .and(warp::path::param())
+    .and(warp::path::param())
+    .and(warp::path("unyank"))
+    .and(warp::path::end())
+    .and(warp::header::optional::<String>("authorization"))
+    .map(move |name: String, version: String, authz| {
+      authorize(&auth_copy, &registry_copy, "v1/crates/unyank", None, authz)?;
+      let mut index = copy.lock().unwrap();
+      let index = index.as_mut().unwrap();
+      publish::unyank_crate(index, &name, &version)
+    })
+    .and_then(response_ok)
+    .with(warp::trace::request());
+
+  let publish = publish.or(download).or(yank).or(unyank).boxed();
+
+  let copy = shared.clone();
+  let admin_list = warp::get()
+    .and(warp::path("admin"))
+    .and(warp::path("crates"))
+    .and(warp::path::end())
+    .map(move || {
+      let index = copy.lock().unwrap();
+      let index = index.as_ref().unwrap();
+      admin_list_crates(index)
+    })
+    .and_then(admin_response)
+    .with(warp::trace::request());
+
+  let copy = shared.clone();
+  let admin_detail = warp::get()
+    .and(warp::path("admin"))
+    .and(warp::path("crates"))
+    .and(warp::path::param())
+    .and(warp::path::end())
+    .map(move |name: String| {
+      let index = copy.lock().unwrap();
+      let index = index.as_ref().unwrap();
+      admin_crate_summary(index, &name)
+    })
+    .and_then(admin_response)
+    .with(warp::trace::request());
+
+  let copy = shared.clone();
+  let admin_remove = warp::delete()
+    .and(warp::path("admin"))
+    .and(warp::path("crates"))
+    .and(warp::path::param())
+    .and(warp::path::param())
+    .and(warp::path::end())
+    .map(move |name: String, version: String| {
+      let mut index = copy.lock().unwrap();
+      let index = index.as_mut().unwrap();
+      index.remove_version(&name, &version)
+    })
+    .and_then(response_ok)
+    .with(warp::trace::request());
+
+  let admin = admin_list.or(admin_detail).or(admin_remove).boxed();
+  // `--admin-addr` implies `--admin`, it just serves the admin API on
+  // a dedicated listener instead of folding it into the regular
+  // routes.
+  let admin_enabled = args.admin || args.admin_addr.is_some();
+
+  // Both branches below are boxed, but boxing only erases the filter
+  // type, not the reply type it extracts, and the two branches'
+  // reply types (a nested `Either` including `sparse_index`'s vs. one
+  // that doesn't) differ and can't unify. Map both down to `Box<dyn
+  // Reply>` first so they agree.
+  let routes = if args.sparse {
+    let root = args.root.clone();
+    let sparse_index = warp::get()
+      .and(warp::path("index"))
+      .and(warp::path::tail())
+      .and(warp::header::optional::<String>("if-none-match"))
+      .map(move |tail: warp::path::Tail, if_none_match| {
+        serve_index_file(&root, tail.as_str(), if_none_match)
+      })
+      .with(warp::trace::request());
+
+    publish
+      .clone()
+      .or(sparse_index)
+      .map(|reply| -> Box<dyn warp::Reply> { Box::new(reply) })
+      .boxed()
+  } else {
+    publish
+      .clone()
+      .map(|reply| -> Box<dyn warp::Reply> { Box::new(reply) })
+      .boxed()
+  };
+
+  let routes = if admin_enabled && args.admin_addr.is_none() {
+    routes
+      .or(admin.clone())
+      .map(|reply| -> Box<dyn warp::Reply> { Box::new(reply) })
+      .boxed()
+  } else {
+    routes
+  };
+
+  let routes = routes.recover(handle_rejection).boxed();
+  let admin_routes = admin.recover(handle_rejection).boxed();
+
   let level = match args.verbosity {
     0 => LevelFilter::WARN,
     1 => LevelFilter::INFO,
@@ -153,12 +699,12 @@ fn run() -> Result<()> {
 
   let subscriber = FmtSubscriber::builder()
     .with_max_level(level)
-    .with_timer(ChronoLocal::rfc3339())
+    .with_timer(ChronoLocal::rfc_3339())
     .finish();
 
   set_global_subscriber(subscriber).with_context(|| "failed to set tracing subscriber")?;
 
-  let mut rt = Runtime::new().unwrap();
+  let rt = Runtime::new().unwrap();
 
   rt.block_on(async move {
     let mut addr = args.addr;
@@ -176,7 +722,7 @@ fn run() -> Result<()> {
       // Despite the claim that this function "Returns [...] a Future that
       // can be executed on any runtime." not even the call itself can
       // happen outside of a tokio runtime. Boy.
-      let result = warp::serve(publish.clone())
+      let result = warp::serve(routes.clone())
         .try_bind_ephemeral(addr)
         .with_context(|| format!("failed to bind to {}", addr));
 
@@ -190,7 +736,7 @@ fn run() -> Result<()> {
       }
     };
 
-    let index = index::Index::new(&args.root, &addr).with_context(|| {
+    let index = index::Index::new(&args.root, &addr, auth.is_some()).with_context(|| {
       format!(
         "failed to create/instantiate crate index at {}",
         args.root.display()
@@ -199,6 +745,15 @@ fn run() -> Result<()> {
 
     *shared.lock().unwrap() = Some(index);
 
+    // `admin_enabled` is implied by `args.admin_addr` being set, so we
+    // only need to check the latter here.
+    if let Some(admin_addr) = args.admin_addr {
+      let (_, admin_serve) = warp::serve(admin_routes)
+        .try_bind_ephemeral(admin_addr)
+        .with_context(|| format!("failed to bind admin API to {}", admin_addr))?;
+      tokio::spawn(admin_serve);
+    }
+
     serve.await;
     Ok(())
   })
@@ -237,21 +792,26 @@ mod tests {
   }
 
   #[test]
-  fn fallback_error_encoding() {
-    let expected = r#"{"errors":[
-    {"detail":"failed to convert internal error to JSON"},
-    {"detail":"foobar"}
-  ]}"#;
-
-    let error = encode_fallback_error("foobar");
-    assert_eq!(error, expected);
-
-    let errors = from_str::<RegistryErrors>(&error).unwrap();
-    assert_eq!(
-      &errors.errors[0].detail,
-      "failed to convert internal error to JSON"
-    );
-    assert_eq!(&errors.errors[1].detail, "foobar");
-    assert_eq!(errors.errors.len(), 2);
+  fn registry_error_encoding_escapes_detail() {
+    let errors = RegistryErrors {
+      errors: vec![RegistryError {
+        detail: r#"crate "foo" has a "bad" manifest"#.to_string(),
+      }],
+    };
+
+    let encoded = to_string(&errors).unwrap();
+    let decoded = from_str::<RegistryErrors>(&encoded).unwrap();
+    assert_eq!(decoded.errors[0].detail, errors.errors[0].detail);
+  }
+
+  #[test]
+  fn registry_errors_from_error_walks_source_chain() {
+    let source = Error::msg("root cause").context("mid layer").context("top layer");
+    let errors = RegistryErrors::from(source);
+
+    assert_eq!(errors.errors.len(), 3);
+    assert_eq!(errors.errors[0].detail, "top layer");
+    assert_eq!(errors.errors[1].detail, "mid layer");
+    assert_eq!(errors.errors[2].detail, "root cause");
   }
 }
@@ -0,0 +1,188 @@
+// Copyright (C) 2020 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Functionality for handling crate publication, i.e., the `PUT
+//! /api/v1/crates/new` endpoint, as well as yanking and unyanking of
+//! published versions.
+
+use std::convert::TryInto as _;
+use std::fs::create_dir_all;
+use std::fs::write;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use bytes::Bytes;
+
+use serde::Deserialize;
+use serde_json::from_slice;
+use serde_json::Value;
+
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::error::Error as RegistryError;
+use crate::index::Index;
+use crate::index::IndexEntry;
+
+
+/// The maximum size we accept for a single `.crate` file. The
+/// publish route additionally caps the overall request body, but we
+/// check the length cargo itself reports once we've split it out, so
+/// that a request with a technically-small body but a bogus, huge
+/// `crate_len` prefix is rejected with a proper error rather than
+/// panicking on a bad slice.
+const MAX_CRATE_SIZE: usize = 2 * 1024 * 1024;
+
+/// The subset of the metadata cargo sends along with a publish
+/// request that we actually care about.
+#[derive(Debug, Deserialize)]
+struct Metadata {
+  name: String,
+  vers: String,
+  #[serde(default)]
+  deps: Value,
+  #[serde(default)]
+  features: Value,
+  #[serde(default)]
+  links: Option<String>,
+}
+
+/// Split the body of a publish request into its metadata JSON and
+/// the raw `.crate` bytes, as described [here][].
+///
+/// [here]: https://doc.rust-lang.org/cargo/reference/registries.html#publish
+fn split(body: &Bytes) -> Result<(Metadata, &[u8])> {
+  let (json_len, rest) = body.split_at(4);
+  let json_len = u32::from_le_bytes(json_len.try_into().unwrap()) as usize;
+  anyhow::ensure!(rest.len() >= json_len, "publish body is truncated");
+
+  let (json, rest) = rest.split_at(json_len);
+  let metadata = from_slice::<Metadata>(json).map_err(|err| RegistryError::InvalidManifest(err.into()))?;
+
+  let (crate_len, rest) = rest.split_at(4);
+  let crate_len = u32::from_le_bytes(crate_len.try_into().unwrap()) as usize;
+  anyhow::ensure!(rest.len() >= crate_len, "`.crate` payload is truncated");
+  if crate_len > MAX_CRATE_SIZE {
+    return Err(RegistryError::CrateTooLarge.into());
+  }
+
+  let (crate_bytes, _) = rest.split_at(crate_len);
+  Ok((metadata, crate_bytes))
+}
+
+/// Peek at the name and version a publish request is for, without
+/// fully processing it. Used to bind an authentication token to the
+/// specific crate/version being uploaded before we commit to storing
+/// anything.
+pub fn peek_metadata(body: &Bytes) -> Result<(String, String)> {
+  let (metadata, _) = split(body)?;
+  Ok((metadata.name, metadata.vers))
+}
+
+/// Persist the uploaded `.crate` file to disk and record the new
+/// version in the index.
+pub fn publish_crate(body: Bytes, index: &mut Index) -> Result<()> {
+  let (metadata, crate_bytes) = split(&body)?;
+
+  if index.contains_version(&metadata.name, &metadata.vers)? {
+    return Err(
+      RegistryError::VersionExists {
+        name: metadata.name,
+        version: metadata.vers,
+      }
+      .into(),
+    );
+  }
+
+  let cksum = Sha256::digest(crate_bytes);
+  let cksum = cksum.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+  let path = index.crate_path(&metadata.name, &metadata.vers);
+  let parent = path
+    .parent()
+    .with_context(|| format!("crate path {} has no parent", path.display()))?;
+  create_dir_all(parent)
+    .with_context(|| format!("failed to create crate directory {}", parent.display()))?;
+  write(&path, crate_bytes)
+    .with_context(|| format!("failed to write crate file {}", path.display()))?;
+
+  let entry = IndexEntry {
+    name: metadata.name,
+    vers: metadata.vers,
+    deps: metadata.deps,
+    cksum,
+    features: metadata.features,
+    yanked: false,
+    links: metadata.links,
+  };
+
+  index.add_entry(&entry)
+}
+
+/// Mark `version` of crate `name` as yanked.
+pub fn yank_crate(index: &mut Index, name: &str, version: &str) -> Result<()> {
+  index.set_yanked(name, version, true)
+}
+
+/// Mark `version` of crate `name` as no longer yanked.
+pub fn unyank_crate(index: &mut Index, name: &str, version: &str) -> Result<()> {
+  index.set_yanked(name, version, false)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+
+  /// Build the wire format cargo uses for a publish request body: a
+  /// 4-byte LE metadata length, the metadata JSON, a 4-byte LE crate
+  /// length, and the crate bytes themselves.
+  fn body(name: &str, vers: &str, crate_bytes: &[u8]) -> Bytes {
+    let metadata = format!(r#"{{"name":"{}","vers":"{}"}}"#, name, vers);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    buf.extend_from_slice(metadata.as_bytes());
+    buf.extend_from_slice(&(crate_bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(crate_bytes);
+    Bytes::from(buf)
+  }
+
+  /// Create a fresh `Index` rooted at a unique temporary directory, so
+  /// that tests running concurrently don't trample each other's
+  /// files.
+  fn temp_index() -> Index {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let root =
+      std::env::temp_dir().join(format!("cargo-http-registry-publish-test-{}-{}", std::process::id(), id));
+    let addr = "127.0.0.1:0".parse().unwrap();
+    Index::new(&root, &addr, false).unwrap()
+  }
+
+  #[test]
+  fn publish_rejects_duplicate_version() {
+    let mut index = temp_index();
+    let body = body("demo", "0.1.0", b"fake crate contents");
+
+    publish_crate(body.clone(), &mut index).unwrap();
+    let err = publish_crate(body, &mut index).unwrap_err();
+    assert!(err.downcast_ref::<RegistryError>().is_some());
+  }
+
+  #[test]
+  fn publish_rejects_oversized_crate() {
+    let mut index = temp_index();
+    let crate_bytes = vec![0u8; MAX_CRATE_SIZE + 1];
+    let body = body("demo", "0.1.0", &crate_bytes);
+
+    let err = publish_crate(body, &mut index).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<RegistryError>(),
+      Some(RegistryError::CrateTooLarge)
+    ));
+  }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2020 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A token-bucket rate limiter protecting the publish endpoint from
+//! being hammered by a single client.
+//!
+//! Buckets are keyed by whatever identifies a client: their IP
+//! address when no authentication is configured, or their PASETO key
+//! id otherwise. Each bucket refills continuously at a configurable
+//! rate, up to a configurable burst size, and a request is admitted
+//! only if at least one token is available.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+
+
+/// A key identifying the client a bucket belongs to.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+  /// The client's IP address, used when authentication is disabled.
+  Addr(IpAddr),
+  /// The authenticated client's PASETO key id.
+  KeyId(String),
+}
+
+/// A single token bucket, tracking how many tokens are currently
+/// available and when it was last refilled.
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl Bucket {
+  fn new(burst: f64) -> Self {
+    Self {
+      tokens: burst,
+      last_refill: Instant::now(),
+    }
+  }
+
+  /// Refill based on elapsed time and attempt to withdraw a single
+  /// token, returning whether the request is admitted.
+  fn try_admit(&mut self, rate: f64, burst: f64) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rate).min(burst);
+    self.last_refill = now;
+
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// The time until at least one token becomes available again, used
+  /// to populate `Retry-After`.
+  fn retry_after(&self, rate: f64) -> Duration {
+    if self.tokens >= 1.0 {
+      Duration::from_secs(0)
+    } else {
+      Duration::from_secs_f64((1.0 - self.tokens) / rate)
+    }
+  }
+}
+
+/// Configuration for the rate limiter.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+  /// Tokens added per second.
+  pub rate: f64,
+  /// Maximum number of tokens a bucket can hold.
+  pub burst: f64,
+}
+
+/// The rate limiter itself: a set of per-key buckets guarded by a
+/// single mutex. This is adequate for the traffic a self-hosted
+/// registry sees; should contention ever become a problem the map
+/// could be sharded or replaced with a `DashMap`.
+#[derive(Debug)]
+pub struct Limiter {
+  limits: Limits,
+  buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl Limiter {
+  pub fn new(limits: Limits) -> Self {
+    Self {
+      limits,
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Check whether a request identified by `key` is currently
+  /// admitted. On denial, returns the duration the client should wait
+  /// before retrying.
+  pub fn check(&self, key: Key) -> Result<(), Duration> {
+    let mut buckets = self.buckets.lock().unwrap();
+    let bucket = buckets
+      .entry(key)
+      .or_insert_with(|| Bucket::new(self.limits.burst));
+
+    if bucket.try_admit(self.limits.rate, self.limits.burst) {
+      Ok(())
+    } else {
+      Err(bucket.retry_after(self.limits.rate))
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn burst_then_throttle() {
+    let limits = Limits { rate: 1.0, burst: 2.0 };
+    let bucket_key = Key::Addr("127.0.0.1".parse().unwrap());
+    let mut bucket = Bucket::new(limits.burst);
+
+    assert!(bucket.try_admit(limits.rate, limits.burst));
+    assert!(bucket.try_admit(limits.rate, limits.burst));
+    assert!(!bucket.try_admit(limits.rate, limits.burst));
+
+    let limiter = Limiter::new(limits);
+    assert!(limiter.check(bucket_key.clone()).is_ok());
+  }
+}